@@ -60,6 +60,13 @@ impl SetupPacket {
             self.args(),
         )
     }
+
+    fn cdc_request(self) -> (CdcRequest, (u16, u16, u16)) {
+        (
+            CdcRequest::from_primitive(self.b_request).unwrap(),
+            self.args(),
+        )
+    }
 }
 
 #[derive(PackedStruct, Clone, Copy, Debug)]
@@ -129,6 +136,13 @@ pub enum HIDRequest {
     SetProtocol = 0xb,
 }
 
+#[derive(PrimitiveEnum_u8, Clone, Copy, Debug, PartialEq)]
+pub enum CdcRequest {
+    SetLineCoding = 0x20,
+    GetLineCoding = 0x21,
+    SetControlLineState = 0x22,
+}
+
 #[derive(PrimitiveEnum, Clone, Copy, Debug)]
 enum DescriptorType {
     Device = 1,
@@ -157,29 +171,163 @@ struct usb_hid_descriptor {
     // ... optional other descriptors type/length pairs
 }
 
-pub struct Device<'a> {
-    pub device_descriptor: usb_device_descriptor,
-    pub config_descriptor: usb_config_descriptor,
-    pub interface_descriptor: usb_interface_descriptor,
+// CDC 1.1 (class 0x02) constants used for the virtual debug-log serial port.
+const USB_CLASS_COMM: u8 = 0x02;
+const USB_CLASS_CDC_DATA: u8 = 0x0a;
+const USB_CDC_SUBCLASS_ACM: u8 = 0x02;
+const CS_INTERFACE: u8 = 0x24;
+const CDC_DT_HEADER: u8 = 0x00;
+const CDC_DT_CALL_MANAGEMENT: u8 = 0x01;
+const CDC_DT_ACM: u8 = 0x02;
+const CDC_DT_UNION: u8 = 0x06;
+const USB_DT_INTERFACE_ASSOCIATION: u8 = 0x0b;
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_snake_case)]
+struct usb_interface_assoc_descriptor {
+    bLength: u8,
+    bDescriptorType: u8,
+    bFirstInterface: u8,
+    bInterfaceCount: u8,
+    bFunctionClass: u8,
+    bFunctionSubClass: u8,
+    bFunctionProtocol: u8,
+    iFunction: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_snake_case)]
+struct cdc_header_descriptor {
+    bFunctionLength: u8,
+    bDescriptorType: u8,
+    bDescriptorSubtype: u8,
+    bcdCDC: u16,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_snake_case)]
+struct cdc_call_management_descriptor {
+    bFunctionLength: u8,
+    bDescriptorType: u8,
+    bDescriptorSubtype: u8,
+    bmCapabilities: u8,
+    bDataInterface: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_snake_case)]
+struct cdc_acm_descriptor {
+    bFunctionLength: u8,
+    bDescriptorType: u8,
+    bDescriptorSubtype: u8,
+    bmCapabilities: u8,
+}
+
+#[repr(C, packed)]
+#[derive(Debug, Copy, Clone)]
+#[allow(non_snake_case)]
+struct cdc_union_descriptor {
+    bFunctionLength: u8,
+    bDescriptorType: u8,
+    bDescriptorSubtype: u8,
+    bControlInterface: u8,
+    bSubordinateInterface0: u8,
+}
+
+const FIDO_INTERFACE_NUMBER: u8 = 0;
+const CDC_COMM_INTERFACE_NUMBER: u8 = 1;
+const CDC_DATA_INTERFACE_NUMBER: u8 = 2;
+
+// The set of descriptor bytes and class-specific descriptor(s) a handler
+// contributes to the device's single configuration. `bytes` holds every
+// interface/class/endpoint descriptor the handler owns, in wire order
+// (including a leading Interface Association Descriptor if the handler
+// groups more than one interface); it does not include the top-level
+// usb_config_descriptor header, which `Device` owns.
+pub struct InterfaceLayout {
+    pub bytes: Vec<u8>,
+    pub num_interfaces: u8,
+    pub report_descriptor: Option<Vec<u8>>,
+}
+
+// A USB function living inside our single emulated configuration. `Device`
+// no longer hardwires CTAPHID: it owns a list of these and routes every URB
+// to whichever handler owns the targeted interface or endpoint. The list is
+// built in `Device::with_config` and `init_callbacks` schedules a fixed set
+// of endpoints, so a new function also needs entries in both places.
+pub trait UsbInterfaceHandler {
+    fn descriptors(&self) -> InterfaceLayout;
+    fn owns_interface(&self, interface_number: u8) -> bool;
+    fn owns_endpoint(&self, endpoint: u8) -> bool;
+    // Whether this handler has unsolicited IN data waiting. `Device` checks
+    // it for endpoint 1 after each ep2 OUT transfer, to decide whether to
+    // unblock the interrupt-IN handler; no other endpoint is checked.
+    fn has_pending_output(&self) -> bool;
+    fn handle_urb(
+        &mut self,
+        endpoint: u8,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> R<Vec<u8>>;
+}
+
+// NOT ACTIONABLE in this tree: replacing the callback-based URB event
+// loop (`init_callbacks`/`Handler`/`URB`/`unblock_handler` below) with an
+// async `Driver`/`Endpoint` abstraction needs `usbip` (the actual USB/IP
+// transport, declared as `pub mod usbip` in lib.rs) to act as the
+// executor that polls per-endpoint tasks and fills in `URB::status`.
+// That module doesn't exist in this tree, so there is no executor
+// boundary to wire async tasks into. Adding the trait pair without it
+// would just be dead code with no caller, so the existing closure-based
+// `Handler`/`URB` dispatch ships unchanged; revisit this once `usbip`
+// lands.
+
+// A log sink shared between handlers, so traffic seen by one
+// `UsbInterfaceHandler` (e.g. CTAPHID URBs/parser state in
+// `CtapHidHandler`) can be streamed out over another's transport (the
+// CDC-ACM debug console's bulk-IN endpoint), not just written to
+// `log!()`/stdout. Cloning shares the same underlying ring buffer.
+#[derive(Clone, Default)]
+struct DebugLog(std::rc::Rc<std::cell::RefCell<std::collections::VecDeque<u8>>>);
+
+impl DebugLog {
+    fn push_line(&self, msg: &str) {
+        let mut buf = self.0.borrow_mut();
+        buf.extend(msg.as_bytes());
+        buf.push_back(b'\n');
+    }
+
+    fn is_empty(&self) -> bool {
+        self.0.borrow().is_empty()
+    }
+
+    fn drain(&self, max: usize) -> Vec<u8> {
+        let mut buf = self.0.borrow_mut();
+        let n = buf.len().min(max);
+        buf.drain(..n).collect()
+    }
+}
+
+struct CtapHidHandler<'a> {
+    interface_descriptor: usb_interface_descriptor,
     hid_descriptor: usb_hid_descriptor,
     hid_report_descriptor: Vec<u8>,
     endpoint_descriptors: Vec<usb_endpoint_descriptor>,
-    strings: Vec<&'static str>,
+    // HID_REQ_GET/SET_PROTOCOL state: 0 = boot protocol, 1 = report protocol
+    protocol: u8,
+    // HID_REQ_GET/SET_IDLE state, keyed by report id
+    idle_rates: std::collections::HashMap<u8, u8>,
     parser: ctaphid::Parser<'a>,
+    // Streams CTAPHID URB/parser-state traffic out the CDC debug console.
+    log: DebugLog,
 }
 
-// USB Request Block
-pub struct URB<T> {
-    pub endpoint: u8,
-    pub setup: SetupPacket,
-    pub transfer_buffer: Vec<u8>,
-    pub complete: Option<Box<dyn FnOnce(Box<URB<T>>)>>,
-    pub context: Box<T>,
-    pub status: Option<R<bool>>, //bool is temporary
-}
-
-impl<'a> Device<'a> {
-    pub fn new(token: &'a Token, prompt: &'a dyn prompt::Prompt) -> Self {
+impl<'a> CtapHidHandler<'a> {
+    fn new(token: &'a Token, prompt: &'a dyn prompt::Prompt, log: DebugLog) -> Self {
         let hid_report_descriptor: Vec<u8> = {
             use hid::*;
             [
@@ -206,46 +354,10 @@ impl<'a> Device<'a> {
         };
 
         Self {
-            device_descriptor: usb_device_descriptor {
-                bLength: size_of::<usb_device_descriptor>() as u8,
-                bDescriptorType: DT::Device.to_primitive(),
-                bcdUSB: 0x0110u16.to_le(),
-                bDeviceClass: USB_CLASS_PER_INTERFACE as u8,
-                bDeviceSubClass: 0,
-                bDeviceProtocol: 0,
-                bMaxPacketSize0: 64,
-                idVendor: 0,
-                idProduct: 0,
-                bcdDevice: 0x001u16.to_le(),
-                iManufacturer: 1,
-                iProduct: 2,
-                iSerialNumber: 3,
-                bNumConfigurations: 1,
-            },
-            config_descriptor: usb_config_descriptor {
-                bLength: size_of::<usb_config_descriptor>() as u8,
-                bDescriptorType: DT::Configuration.to_primitive(),
-                wTotalLength: u16::try_from(
-                    size_of::<usb_config_descriptor>()
-                        + size_of::<usb_interface_descriptor>()
-                        + size_of::<usb_hid_descriptor>()
-                        //+ hid_report_descriptor.len()
-                        + 2 * USB_DT_ENDPOINT_SIZE as usize,
-                )
-                .unwrap()
-                .to_le(),
-                bNumInterfaces: 1,
-                bConfigurationValue: 0,
-                iConfiguration: 4,
-                bmAttributes: (USB_CONFIG_ATT_ONE
-                    | USB_CONFIG_ATT_SELFPOWER)
-                    as u8,
-                bMaxPower: 0,
-            },
             interface_descriptor: usb_interface_descriptor {
                 bLength: size_of::<usb_interface_descriptor>() as u8,
                 bDescriptorType: DT::Interface.to_primitive(),
-                bInterfaceNumber: 0,
+                bInterfaceNumber: FIDO_INTERFACE_NUMBER,
                 bAlternateSetting: 0,
                 bNumEndpoints: 2,
                 bInterfaceClass: USB_CLASS_HID as u8,
@@ -264,7 +376,7 @@ impl<'a> Device<'a> {
                     as u16)
                     .to_le(),
             },
-            hid_report_descriptor: hid_report_descriptor,
+            hid_report_descriptor,
             endpoint_descriptors: vec![
                 usb_endpoint_descriptor {
                     bLength: USB_DT_ENDPOINT_SIZE as u8,
@@ -294,15 +406,535 @@ impl<'a> Device<'a> {
                     bSynchAddress: 0,
                 },
             ],
+            protocol: 1,
+            idle_rates: std::collections::HashMap::new(),
+            parser: ctaphid::Parser::new(token, prompt),
+            log,
+        }
+    }
+
+    fn handle_control(&mut self, req: SetupPacket, data: &[u8]) -> R<Vec<u8>> {
+        match req.request_type() {
+            (D2H, RT::Class, RR::Interface) => {
+                let (hid_req, (w_value, _w_index, w_length)) =
+                    req.hid_request();
+                let [report_id, report_type] = w_value.to_le_bytes();
+                match hid_req {
+                    HIDRequest::GetReport if report_type == 1 => {
+                        // Input report: hand over the next pending
+                        // CTAPHID frame, running the parser first if
+                        // nothing is queued yet.
+                        while self.parser.send_queue.is_empty()
+                            && !self.parser.recv_queue.is_empty()
+                        {
+                            self.parser.parse()?
+                        }
+                        let mut frame =
+                            self.parser.send_queue.pop_front().unwrap_or_default();
+                        frame.truncate(w_length as usize);
+                        Ok(frame)
+                    }
+                    HIDRequest::GetProtocol => Ok(vec![self.protocol]),
+                    HIDRequest::GetIdle => Ok(vec![*self
+                        .idle_rates
+                        .get(&report_id)
+                        .unwrap_or(&0)]),
+                    // Output/feature reports, and any other HID request
+                    // we deliberately don't support: stall the transfer
+                    // instead of panicking the whole process.
+                    hid_req => Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!(
+                            "unsupported HID control request {:?} (report_type {})",
+                            hid_req, report_type
+                        ),
+                    )
+                    .into()),
+                }
+            }
+            (H2D, RT::Class, RR::Interface) => {
+                let (hid_req, (w_value, _w_index, _w_length)) =
+                    req.hid_request();
+                match hid_req {
+                    // CTAPHID fallback path: a host that talks to us over
+                    // the control endpoint instead of the interrupt-OUT
+                    // endpoint feeds the same receive queue.
+                    HIDRequest::SetReport => {
+                        self.parser.recv_queue.push_back(data.to_vec());
+                        Ok(Vec::new())
+                    }
+                    HIDRequest::SetProtocol => {
+                        let [protocol, _reserved] = w_value.to_le_bytes();
+                        self.protocol = protocol;
+                        Ok(Vec::new())
+                    }
+                    HIDRequest::SetIdle => {
+                        let [report_id, idle_rate] = w_value.to_le_bytes();
+                        self.idle_rates.insert(report_id, idle_rate);
+                        Ok(Vec::new())
+                    }
+                    // GetReport/GetIdle/GetProtocol with the wrong
+                    // direction bit, and anything else: stall rather
+                    // than panic the process.
+                    hid_req => Err(std::io::Error::new(
+                        std::io::ErrorKind::Unsupported,
+                        format!(
+                            "unsupported HID control request {:?} (H2D)",
+                            hid_req
+                        ),
+                    )
+                    .into()),
+                }
+            }
+            _ => unimplemented!(),
+        }
+    }
+
+    fn ep_in(&mut self) -> R<Vec<u8>> {
+        log!("ep1 dev->host");
+        self.log.push_line("ep1 dev->host");
+        while !self.parser.recv_queue.is_empty() {
+            self.parser.parse()?
+        }
+        if self.parser.send_queue.is_empty() {
+            Ok(Vec::new())
+        } else {
+            let mut buf = vec![0u8; 64];
+            self.parser.unparse(&mut buf)?;
+            Ok(buf)
+        }
+    }
+
+    fn ep_out(&mut self, data: &[u8]) -> R<Vec<u8>> {
+        log!("ep2 host->dev");
+        self.log.push_line(&format!("ep2 host->dev {} bytes", data.len()));
+        self.parser.recv_queue.push_back(data.to_vec());
+        Ok(Vec::new())
+    }
+}
+
+impl<'a> UsbInterfaceHandler for CtapHidHandler<'a> {
+    fn descriptors(&self) -> InterfaceLayout {
+        let mut bytes = Vec::new();
+        write_struct(&mut bytes, &self.interface_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.hid_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        for epd in &self.endpoint_descriptors {
+            write_struct_limited(&mut bytes, epd, epd.bLength as usize)
+                .expect("writing to a Vec<u8> cannot fail");
+        }
+        InterfaceLayout {
+            bytes,
+            num_interfaces: 1,
+            report_descriptor: Some(self.hid_report_descriptor.clone()),
+        }
+    }
+
+    fn owns_interface(&self, interface_number: u8) -> bool {
+        interface_number == FIDO_INTERFACE_NUMBER
+    }
+
+    fn owns_endpoint(&self, endpoint: u8) -> bool {
+        endpoint == 1 || endpoint == 2
+    }
+
+    fn has_pending_output(&self) -> bool {
+        !self.parser.send_queue.is_empty() || !self.parser.recv_queue.is_empty()
+    }
+
+    fn handle_urb(
+        &mut self,
+        endpoint: u8,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> R<Vec<u8>> {
+        match endpoint {
+            0 => self.handle_control(setup, data),
+            1 => self.ep_in(),
+            2 => self.ep_out(data),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+struct CdcAcmHandler {
+    iad: usb_interface_assoc_descriptor,
+    comm_interface_descriptor: usb_interface_descriptor,
+    header_descriptor: cdc_header_descriptor,
+    call_management_descriptor: cdc_call_management_descriptor,
+    acm_descriptor: cdc_acm_descriptor,
+    union_descriptor: cdc_union_descriptor,
+    notification_endpoint: usb_endpoint_descriptor,
+    data_interface_descriptor: usb_interface_descriptor,
+    data_endpoint_descriptors: Vec<usb_endpoint_descriptor>,
+    // wire format of SET_LINE_CODING / GET_LINE_CODING (CDC120 6.2.13):
+    // dwDTERate (4 bytes LE), bCharFormat, bParityType, bDataBits
+    line_coding: [u8; 7],
+    control_line_state: u16,
+    // Shared with `CtapHidHandler` so CTAPHID traffic streams out this
+    // handler's bulk-IN endpoint alongside its own ep5 writes.
+    log: DebugLog,
+}
+
+impl CdcAcmHandler {
+    fn new(log: DebugLog) -> Self {
+        Self {
+            iad: usb_interface_assoc_descriptor {
+                bLength: size_of::<usb_interface_assoc_descriptor>() as u8,
+                bDescriptorType: USB_DT_INTERFACE_ASSOCIATION,
+                bFirstInterface: CDC_COMM_INTERFACE_NUMBER,
+                bInterfaceCount: 2,
+                bFunctionClass: USB_CLASS_COMM,
+                bFunctionSubClass: USB_CDC_SUBCLASS_ACM,
+                bFunctionProtocol: 0,
+                iFunction: 6,
+            },
+            comm_interface_descriptor: usb_interface_descriptor {
+                bLength: size_of::<usb_interface_descriptor>() as u8,
+                bDescriptorType: DT::Interface.to_primitive(),
+                bInterfaceNumber: CDC_COMM_INTERFACE_NUMBER,
+                bAlternateSetting: 0,
+                bNumEndpoints: 1,
+                bInterfaceClass: USB_CLASS_COMM,
+                bInterfaceSubClass: USB_CDC_SUBCLASS_ACM,
+                bInterfaceProtocol: 0,
+                iInterface: 6,
+            },
+            header_descriptor: cdc_header_descriptor {
+                bFunctionLength: size_of::<cdc_header_descriptor>() as u8,
+                bDescriptorType: CS_INTERFACE,
+                bDescriptorSubtype: CDC_DT_HEADER,
+                bcdCDC: 0x0110u16.to_le(),
+            },
+            call_management_descriptor: cdc_call_management_descriptor {
+                bFunctionLength: size_of::<cdc_call_management_descriptor>()
+                    as u8,
+                bDescriptorType: CS_INTERFACE,
+                bDescriptorSubtype: CDC_DT_CALL_MANAGEMENT,
+                bmCapabilities: 0,
+                bDataInterface: CDC_DATA_INTERFACE_NUMBER,
+            },
+            acm_descriptor: cdc_acm_descriptor {
+                bFunctionLength: size_of::<cdc_acm_descriptor>() as u8,
+                bDescriptorType: CS_INTERFACE,
+                bDescriptorSubtype: CDC_DT_ACM,
+                bmCapabilities: 0x02, // supports Set/Get_Line_Coding, Set_Control_Line_State
+            },
+            union_descriptor: cdc_union_descriptor {
+                bFunctionLength: size_of::<cdc_union_descriptor>() as u8,
+                bDescriptorType: CS_INTERFACE,
+                bDescriptorSubtype: CDC_DT_UNION,
+                bControlInterface: CDC_COMM_INTERFACE_NUMBER,
+                bSubordinateInterface0: CDC_DATA_INTERFACE_NUMBER,
+            },
+            notification_endpoint: usb_endpoint_descriptor {
+                bLength: USB_DT_ENDPOINT_SIZE as u8,
+                bDescriptorType: DT::Endpoint.to_primitive(),
+                bEndpointAddress: ((3 & USB_ENDPOINT_NUMBER_MASK)
+                    | (USB_DIR_IN & USB_ENDPOINT_DIR_MASK))
+                    as u8,
+                bmAttributes: USB_ENDPOINT_XFER_INT as u8,
+                wMaxPacketSize: ((8 & USB_ENDPOINT_MAXP_MASK) as u16)
+                    .to_le(),
+                bInterval: 255,
+                bRefresh: 0,
+                bSynchAddress: 0,
+            },
+            data_interface_descriptor: usb_interface_descriptor {
+                bLength: size_of::<usb_interface_descriptor>() as u8,
+                bDescriptorType: DT::Interface.to_primitive(),
+                bInterfaceNumber: CDC_DATA_INTERFACE_NUMBER,
+                bAlternateSetting: 0,
+                bNumEndpoints: 2,
+                bInterfaceClass: USB_CLASS_CDC_DATA,
+                bInterfaceSubClass: 0,
+                bInterfaceProtocol: 0,
+                iInterface: 0,
+            },
+            data_endpoint_descriptors: vec![
+                usb_endpoint_descriptor {
+                    bLength: USB_DT_ENDPOINT_SIZE as u8,
+                    bDescriptorType: DT::Endpoint.to_primitive(),
+                    bEndpointAddress: ((4 & USB_ENDPOINT_NUMBER_MASK)
+                        | (USB_DIR_IN & USB_ENDPOINT_DIR_MASK))
+                        as u8,
+                    bmAttributes: USB_ENDPOINT_XFER_BULK as u8,
+                    wMaxPacketSize: ((64 & USB_ENDPOINT_MAXP_MASK) as u16)
+                        .to_le(),
+                    bInterval: 0,
+                    bRefresh: 0,
+                    bSynchAddress: 0,
+                },
+                usb_endpoint_descriptor {
+                    bLength: USB_DT_ENDPOINT_SIZE as u8,
+                    bDescriptorType: DT::Endpoint.to_primitive(),
+                    bEndpointAddress: ((5 & USB_ENDPOINT_NUMBER_MASK)
+                        | (USB_DIR_OUT & USB_ENDPOINT_DIR_MASK))
+                        as u8,
+                    bmAttributes: USB_ENDPOINT_XFER_BULK as u8,
+                    wMaxPacketSize: ((64 & USB_ENDPOINT_MAXP_MASK) as u16)
+                        .to_le(),
+                    bInterval: 0,
+                    bRefresh: 0,
+                    bSynchAddress: 0,
+                },
+            ],
+            // 115200 baud, 1 stop bit, no parity, 8 data bits
+            line_coding: [0x00, 0xc2, 0x01, 0x00, 0, 0, 8],
+            control_line_state: 0,
+            log,
+        }
+    }
+
+    // Append a line to the ring buffer drained by the debug-console
+    // bulk-IN endpoint, so a host can `cat` the serial port to watch CDC
+    // traffic without recompiling the crate.
+    fn push_log(&mut self, msg: &str) {
+        self.log.push_line(msg);
+    }
+
+    fn handle_control(&mut self, req: SetupPacket, data: &[u8]) -> R<Vec<u8>> {
+        match req.request_type() {
+            (D2H, RT::Class, RR::Interface) => match req.cdc_request() {
+                (CdcRequest::GetLineCoding, _) => Ok(self.line_coding.to_vec()),
+                _ => unimplemented!(),
+            },
+            (H2D, RT::Class, RR::Interface) => match req.cdc_request() {
+                (CdcRequest::SetLineCoding, _) => {
+                    let len = self.line_coding.len().min(data.len());
+                    self.line_coding[..len].copy_from_slice(&data[..len]);
+                    Ok(Vec::new())
+                }
+                (CdcRequest::SetControlLineState, (state, _, 0)) => {
+                    self.control_line_state = state;
+                    Ok(Vec::new())
+                }
+                _ => unimplemented!(),
+            },
+            _ => unimplemented!(),
+        }
+    }
+
+}
+
+impl UsbInterfaceHandler for CdcAcmHandler {
+    fn descriptors(&self) -> InterfaceLayout {
+        let mut bytes = Vec::new();
+        write_struct(&mut bytes, &self.iad)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.comm_interface_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.header_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.call_management_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.acm_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.union_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        write_struct_limited(
+            &mut bytes,
+            &self.notification_endpoint,
+            self.notification_endpoint.bLength as usize,
+        )
+        .expect("writing to a Vec<u8> cannot fail");
+        write_struct(&mut bytes, &self.data_interface_descriptor)
+            .expect("writing to a Vec<u8> cannot fail");
+        for epd in &self.data_endpoint_descriptors {
+            write_struct_limited(&mut bytes, epd, epd.bLength as usize)
+                .expect("writing to a Vec<u8> cannot fail");
+        }
+        InterfaceLayout {
+            bytes,
+            num_interfaces: 2,
+            report_descriptor: None,
+        }
+    }
+
+    fn owns_interface(&self, interface_number: u8) -> bool {
+        interface_number == CDC_COMM_INTERFACE_NUMBER
+            || interface_number == CDC_DATA_INTERFACE_NUMBER
+    }
+
+    fn owns_endpoint(&self, endpoint: u8) -> bool {
+        endpoint == 3 || endpoint == 4 || endpoint == 5
+    }
+
+    fn has_pending_output(&self) -> bool {
+        !self.log.is_empty()
+    }
+
+    fn handle_urb(
+        &mut self,
+        endpoint: u8,
+        setup: SetupPacket,
+        data: &[u8],
+    ) -> R<Vec<u8>> {
+        match endpoint {
+            0 => self.handle_control(setup, data),
+            4 => Ok(self.log.drain(64)),
+            5 => {
+                self.push_log(&format!(
+                    "ep5 host->dev {} bytes",
+                    data.len()
+                ));
+                Ok(Vec::new())
+            }
+            3 => Ok(Vec::new()),
+            _ => unimplemented!(),
+        }
+    }
+}
+
+pub struct Device<'a> {
+    pub device_descriptor: usb_device_descriptor,
+    pub config_descriptor: usb_config_descriptor,
+    strings: Vec<String>,
+    handlers: Vec<Box<dyn UsbInterfaceHandler + 'a>>,
+}
+
+// Where the value served for `iSerialNumber` comes from. `Derived` ties the
+// serial to the token so re-plugging the same token always enumerates with
+// the same identity; `Fixed` lets a caller impersonate a specific device.
+pub enum SerialSource {
+    Derived,
+    Fixed(String),
+}
+
+// Lets a caller make the emulated device enumerate as a specific real
+// authenticator (VID/PID, strings, serial) instead of the crate's own
+// placeholder identity. Construct with `Default::default()` and override
+// only the fields that matter.
+pub struct DeviceConfig {
+    pub id_vendor: u16,
+    pub id_product: u16,
+    pub bcd_device: u16,
+    pub manufacturer: String,
+    pub product: String,
+    pub serial: SerialSource,
+}
+
+impl Default for DeviceConfig {
+    fn default() -> Self {
+        Self {
+            id_vendor: 0,
+            id_product: 0,
+            bcd_device: 0x0001,
+            manufacturer: "Fakecompany".to_string(),
+            product: "Softproduct".to_string(),
+            serial: SerialSource::Derived,
+        }
+    }
+}
+
+// FNV-1a, 64-bit variant (published spec: http://www.isthe.com/chongo/tech/comp/fnv/).
+// Unlike `std::collections::hash_map::DefaultHasher`, which the standard
+// library explicitly does not guarantee to be stable across Rust
+// versions, this algorithm's output is fixed by spec, so it's safe to
+// bake into an on-the-wire identity.
+fn fnv1a_64(bytes: &[u8]) -> u64 {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf29ce484222325;
+    const FNV_PRIME: u64 = 0x100000001b3;
+    let mut hash = FNV_OFFSET_BASIS;
+    for &b in bytes {
+        hash ^= b as u64;
+        hash = hash.wrapping_mul(FNV_PRIME);
+    }
+    hash
+}
+
+// A stable, reproducible serial number derived from the token's public key
+// material, so the same token always enumerates with the same identity
+// without us having to persist a serial anywhere ourselves.
+fn derive_serial(token: &Token) -> String {
+    format!("{:016x}", fnv1a_64(&token.public_key_bytes()))
+}
+
+// USB Request Block
+pub struct URB<T> {
+    pub endpoint: u8,
+    pub setup: SetupPacket,
+    pub transfer_buffer: Vec<u8>,
+    pub complete: Option<Box<dyn FnOnce(Box<URB<T>>)>>,
+    pub context: Box<T>,
+    pub status: Option<R<bool>>, //bool is temporary
+}
+
+impl<'a> Device<'a> {
+    pub fn new(token: &'a Token, prompt: &'a dyn prompt::Prompt) -> Self {
+        Self::with_config(token, prompt, DeviceConfig::default())
+    }
+
+    pub fn with_config(
+        token: &'a Token,
+        prompt: &'a dyn prompt::Prompt,
+        config: DeviceConfig,
+    ) -> Self {
+        // Shared so CTAPHID traffic (CtapHidHandler) streams out the CDC
+        // debug console (CdcAcmHandler) instead of only going to stdout.
+        let debug_log = DebugLog::default();
+        let handlers: Vec<Box<dyn UsbInterfaceHandler + 'a>> = vec![
+            Box::new(CtapHidHandler::new(token, prompt, debug_log.clone())),
+            Box::new(CdcAcmHandler::new(debug_log)),
+        ];
+
+        let num_interfaces: u8 =
+            handlers.iter().map(|h| h.descriptors().num_interfaces).sum();
+        let body_len: usize =
+            handlers.iter().map(|h| h.descriptors().bytes.len()).sum();
+
+        let serial = match config.serial {
+            SerialSource::Derived => derive_serial(token),
+            SerialSource::Fixed(s) => s,
+        };
+
+        Self {
+            device_descriptor: usb_device_descriptor {
+                bLength: size_of::<usb_device_descriptor>() as u8,
+                bDescriptorType: DT::Device.to_primitive(),
+                bcdUSB: 0x0110u16.to_le(),
+                bDeviceClass: USB_CLASS_PER_INTERFACE as u8,
+                bDeviceSubClass: 0,
+                bDeviceProtocol: 0,
+                bMaxPacketSize0: 64,
+                idVendor: config.id_vendor.to_le(),
+                idProduct: config.id_product.to_le(),
+                bcdDevice: config.bcd_device.to_le(),
+                iManufacturer: 1,
+                iProduct: 2,
+                iSerialNumber: 3,
+                bNumConfigurations: 1,
+            },
+            // wTotalLength/bNumInterfaces are derived from the handler set
+            // above rather than kept in sync by hand, so plugging in a new
+            // UsbInterfaceHandler can't silently desync the descriptor.
+            config_descriptor: usb_config_descriptor {
+                bLength: size_of::<usb_config_descriptor>() as u8,
+                bDescriptorType: DT::Configuration.to_primitive(),
+                wTotalLength: u16::try_from(
+                    size_of::<usb_config_descriptor>() + body_len,
+                )
+                .unwrap()
+                .to_le(),
+                bNumInterfaces: num_interfaces,
+                bConfigurationValue: 0,
+                iConfiguration: 4,
+                bmAttributes: (USB_CONFIG_ATT_ONE
+                    | USB_CONFIG_ATT_SELFPOWER)
+                    as u8,
+                bMaxPower: 0,
+            },
             strings: vec![
-                "string0",
-                "Fakecompany",
-                "Softproduct",
-                "v0",
-                "Default Config",
-                "The Interface",
+                "string0".to_string(),
+                config.manufacturer,
+                config.product,
+                serial,
+                "Default Config".to_string(),
+                "The Interface".to_string(),
+                "Debug Console".to_string(),
             ],
-            parser: ctaphid::Parser::new(token, prompt),
+            handlers,
         }
     }
 
@@ -328,7 +960,9 @@ impl<'a> Device<'a> {
         let h2d = eventloop::Handler::Host2Dev(
             0,
             |el: &mut eventloop::EventLoop<Device>, mut urb| {
-                let r = el.state.ep0_host2dev(urb.setup);
+                let r = el
+                    .state
+                    .ep0_host2dev(urb.setup, &urb.transfer_buffer);
                 urb.status = Some(match r {
                     Ok(()) => Ok(true),
                     Err(e) => Err(e.into()),
@@ -341,7 +975,7 @@ impl<'a> Device<'a> {
         let d2h1 = eventloop::Handler::Dev2Host(
             1,
             |el: &mut eventloop::EventLoop<Device>, mut urb| {
-                let r = el.state.ep1_dev2host(&mut urb.transfer_buffer);
+                let r = el.state.endpoint_dev2host(1, &mut urb.transfer_buffer);
                 urb.status = Some(match r {
                     Err(e) => Err(e.into()),
                     Ok(x) => Ok(x),
@@ -354,10 +988,8 @@ impl<'a> Device<'a> {
         let h2d2 = eventloop::Handler::Host2Dev(
             2,
             |el: &mut eventloop::EventLoop<Device>, mut urb| {
-                let r = el.state.ep2_host2dev(&urb.transfer_buffer);
-                if !el.state.parser.recv_queue.is_empty()
-                    || !el.state.parser.send_queue.is_empty()
-                {
+                let r = el.state.endpoint_host2dev(2, &urb.transfer_buffer);
+                if el.state.has_pending_output(1) {
                     el.unblock_handler(1, true);
                 };
                 urb.status = Some(match r {
@@ -369,6 +1001,32 @@ impl<'a> Device<'a> {
             },
         );
         el.schedule(h2d2);
+        let d2h4 = eventloop::Handler::Dev2Host(
+            4,
+            |el: &mut eventloop::EventLoop<Device>, mut urb| {
+                let r = el.state.endpoint_dev2host(4, &mut urb.transfer_buffer);
+                urb.status = Some(match r {
+                    Err(e) => Err(e.into()),
+                    Ok(x) => Ok(x),
+                });
+                let complete = urb.complete.take().unwrap();
+                complete(urb)
+            },
+        );
+        el.schedule(d2h4);
+        let h2d5 = eventloop::Handler::Host2Dev(
+            5,
+            |el: &mut eventloop::EventLoop<Device>, mut urb| {
+                let r = el.state.endpoint_host2dev(5, &urb.transfer_buffer);
+                urb.status = Some(match r {
+                    Err(e) => Err(e.into()),
+                    Ok(x) => Ok(x),
+                });
+                let complete = urb.complete.take().unwrap();
+                complete(urb);
+            },
+        );
+        el.schedule(h2d5);
     }
 
     fn get_lang_descriptor(&self, sink: &mut dyn Write) -> IOR<()> {
@@ -386,18 +1044,27 @@ impl<'a> Device<'a> {
         sink: &mut dyn Write,
     ) -> IOR<()> {
         assert!(index > 0);
-        let text = self.strings[index as usize];
-        let utf16_len = text.encode_utf16().count();
-        let mut v = Vec::<u8>::with_capacity(utf16_len);
-        text.encode_utf16().for_each(|u| {
+        let text = &self.strings[index as usize];
+        // bLength is a single byte covering the 2-byte header plus the
+        // UTF-16 payload, so at most (u8::MAX - 2) / 2 code units fit;
+        // anything longer is truncated rather than letting bLength wrap
+        // and emitting a corrupt descriptor.
+        const MAX_UTF16_LEN: usize = (u8::MAX as usize - 2) / 2;
+        let mut units: Vec<u16> = text.encode_utf16().collect();
+        if units.len() > MAX_UTF16_LEN {
+            units.truncate(MAX_UTF16_LEN);
+            // Don't leave an unpaired high surrogate at the cut point.
+            if matches!(units.last(), Some(0xD800..=0xDBFF)) {
+                units.pop();
+            }
+        }
+        let mut v = Vec::<u8>::with_capacity(units.len() * 2);
+        for u in units {
             let bs = u.to_le_bytes();
             v.push(bs[0]);
-            v.push(bs[1])
-        });
-        sink.write_all(&[
-            2 + (utf16_len * 2) as u8,
-            DT::String.to_primitive(),
-        ])?;
+            v.push(bs[1]);
+        }
+        sink.write_all(&[(2 + v.len()) as u8, DT::String.to_primitive()])?;
         sink.write_all(&v)
     }
 
@@ -419,11 +1086,8 @@ impl<'a> Device<'a> {
             (Configuration, 0, 0) => {
                 write_struct(sink, &self.config_descriptor)?;
                 if has_room(sink) {
-                    write_struct(sink, &self.interface_descriptor)?;
-                    write_struct(sink, &self.hid_descriptor)?;
-                    for epd in self.endpoint_descriptors.iter() {
-                        let len = epd.bLength as usize;
-                        write_struct_limited(sink, epd, len)?
+                    for h in self.handlers.iter() {
+                        sink.write_all(&h.descriptors().bytes)?
                     }
                 }
                 Ok(())
@@ -441,18 +1105,83 @@ impl<'a> Device<'a> {
         req: SetupPacket,
         mut out: &mut [u8],
     ) -> IOR<()> {
-        let (value, _, _) = req.args();
+        let (value, index, _) = req.args();
         let [_, desctype] = value.to_le_bytes();
         match desctype as u32 {
-            HID_DT_REPORT => out.write_all(&self.hid_report_descriptor),
+            HID_DT_REPORT => {
+                let interface = index as u8;
+                let report = self
+                    .handlers
+                    .iter()
+                    .find(|h| h.owns_interface(interface))
+                    .and_then(|h| h.descriptors().report_descriptor)
+                    .unwrap_or_else(|| {
+                        panic!(
+                            "interface {} has no report descriptor",
+                            interface
+                        )
+                    });
+                out.write_all(&report)
+            }
             x => panic!("Unsupported descriptor type: {}", x),
         }
     }
 
-    fn ep0_dev2host(&self, req: SetupPacket, sink: &mut [u8]) -> IOR<()> {
+    // Find the handler that owns `interface` and hand it the control
+    // request; used for every Class/Interface request on ep0.
+    fn dispatch_control(
+        &mut self,
+        interface: u8,
+        req: SetupPacket,
+        data: &[u8],
+    ) -> R<Vec<u8>> {
+        self.handlers
+            .iter_mut()
+            .find(|h| h.owns_interface(interface))
+            .unwrap_or_else(|| panic!("no handler owns interface {}", interface))
+            .handle_urb(0, req, data)
+    }
+
+    fn endpoint_dev2host(&mut self, endpoint: u8, buf: &mut [u8]) -> R<bool> {
+        const NULL_SETUP: [u8; 8] = [0; 8];
+        let setup = SetupPacket::unpack(&NULL_SETUP).unwrap();
+        let reply = self
+            .handlers
+            .iter_mut()
+            .find(|h| h.owns_endpoint(endpoint))
+            .unwrap_or_else(|| panic!("no handler owns endpoint {}", endpoint))
+            .handle_urb(endpoint, setup, &[])?;
+        if reply.is_empty() {
+            Ok(false)
+        } else {
+            let n = buf.len().min(reply.len());
+            buf[..n].copy_from_slice(&reply[..n]);
+            Ok(true)
+        }
+    }
+
+    fn endpoint_host2dev(&mut self, endpoint: u8, data: &[u8]) -> R<bool> {
+        const NULL_SETUP: [u8; 8] = [0; 8];
+        let setup = SetupPacket::unpack(&NULL_SETUP).unwrap();
+        self.handlers
+            .iter_mut()
+            .find(|h| h.owns_endpoint(endpoint))
+            .unwrap_or_else(|| panic!("no handler owns endpoint {}", endpoint))
+            .handle_urb(endpoint, setup, data)?;
+        Ok(true)
+    }
+
+    fn has_pending_output(&self, endpoint: u8) -> bool {
+        self.handlers
+            .iter()
+            .find(|h| h.owns_endpoint(endpoint))
+            .map_or(false, |h| h.has_pending_output())
+    }
+
+    fn ep0_dev2host(&mut self, req: SetupPacket, sink: &mut [u8]) -> R<()> {
         match req.request_type() {
             (D2H, RT::Standard, RR::Device) => match req.std() {
-                SR::GetDescriptor => self.get_descriptor(req, sink),
+                SR::GetDescriptor => Ok(self.get_descriptor(req, sink)?),
                 SR::GetStatus if matches!(req.args(), (0, 0, 2)) => {
                     Ok(sink.copy_from_slice(&[1u8, 0]))
                 }
@@ -460,52 +1189,40 @@ impl<'a> Device<'a> {
             },
             (D2H, RT::Standard, RR::Interface) => match req.std() {
                 SR::GetDescriptor => {
-                    self.get_interface_descriptor(req, sink)
+                    Ok(self.get_interface_descriptor(req, sink)?)
                 }
                 _ => unimplemented!(),
             },
+            (D2H, RT::Class, RR::Interface) => {
+                let interface = req.args().1 as u8;
+                let reply = self.dispatch_control(interface, req, &[])?;
+                let len = sink.len().min(reply.len());
+                Ok(sink[..len].copy_from_slice(&reply[..len]))
+            }
             x => panic!("Unsupported request: {:?}", x),
         }
     }
 
-    fn ep0_host2dev(&self, req: SetupPacket) -> IOR<()> {
+    fn ep0_host2dev(&mut self, req: SetupPacket, data: &[u8]) -> R<()> {
         match req.request_type() {
             (H2D, RT::Standard, RR::Device) => match req.std() {
                 SR::SetConfiguration if req.args() == (0, 0, 0) => Ok(()),
                 _ => unimplemented!(),
             },
-            (H2D, RT::Class, RR::Interface) => match req.hid_request() {
-                (HIDRequest::SetIdle, (0, 0, 0)) => Ok(()),
-                _ => unimplemented!(),
-            },
+            (H2D, RT::Class, RR::Interface) => {
+                let interface = req.args().1 as u8;
+                self.dispatch_control(interface, req, data)?;
+                Ok(())
+            }
             _ => unimplemented!(),
         }
     }
-
-    fn ep1_dev2host(&mut self, buf: &mut [u8]) -> R<bool> {
-        log!("ep1 dev->host");
-        while !self.parser.recv_queue.is_empty() {
-            self.parser.parse()?
-        }
-        if !self.parser.send_queue.is_empty() {
-            self.parser.unparse(buf)?;
-            Ok(true)
-        } else {
-            Ok(false)
-        }
-    }
-
-    fn ep2_host2dev(&mut self, data: &[u8]) -> R<bool> {
-        log!("ep2 host->dev");
-        self.parser.recv_queue.push_back(data.to_vec());
-        Ok(true)
-    }
 }
 
 #[test]
 fn test_get_device_descriptor() {
     let token = crate::crypto::tests::get_token().unwrap();
-    let dev = Device::new(&token, &prompt::Pinentry {});
+    let mut dev = Device::new(&token, &prompt::Pinentry {});
     let mut sink = [0u8; size_of::<usb_device_descriptor>()];
     const GET_DEVICE_DESCRIPTOR: &[u8; 8] =
         include_bytes!("../poke/get-device-descriptor.dat");
@@ -518,3 +1235,133 @@ fn test_get_device_descriptor() {
     assert_eq!(d.bNumConfigurations, 1);
     ()
 }
+
+#[test]
+fn test_config_descriptor_interface_counts() {
+    // One FIDO interface (CtapHidHandler) plus the CDC-ACM comm/data pair
+    // (CdcAcmHandler) should add up to three interfaces, and wTotalLength
+    // must cover the config header plus every handler's descriptor bytes.
+    let token = crate::crypto::tests::get_token().unwrap();
+    let dev = Device::new(&token, &prompt::Pinentry {});
+    assert_eq!(dev.config_descriptor.bNumInterfaces, 3);
+    let body_len: usize =
+        dev.handlers.iter().map(|h| h.descriptors().bytes.len()).sum();
+    assert_eq!(
+        u16::from_le(dev.config_descriptor.wTotalLength) as usize,
+        size_of::<usb_config_descriptor>() + body_len
+    );
+}
+
+// bmRequestType = Class | Interface, with the given direction bit
+// (0xa1 = D2H, 0x21 = H2D): direction bit7, type bits 5..=6, recipient
+// bits 0..=4 (see `BmRequestType`).
+fn hid_setup_dir(bm_request_type: u8, b_request: u8, w_value: u16) -> SetupPacket {
+    let mut bytes = [0u8; 8];
+    bytes[0] = bm_request_type;
+    bytes[1] = b_request;
+    bytes[2..4].copy_from_slice(&w_value.to_le_bytes());
+    bytes[4..6].copy_from_slice(&0u16.to_le_bytes()); // wIndex
+    bytes[6..8].copy_from_slice(&64u16.to_le_bytes()); // wLength
+    SetupPacket::unpack(&bytes).unwrap()
+}
+
+fn hid_setup(b_request: u8, w_value: u16) -> SetupPacket {
+    hid_setup_dir(0xa1, b_request, w_value)
+}
+
+#[test]
+fn test_ctaphid_idle_and_protocol_round_trip() {
+    let token = crate::crypto::tests::get_token().unwrap();
+    let mut handler =
+        CtapHidHandler::new(&token, &prompt::Pinentry {}, DebugLog::default());
+
+    // SetProtocol(0) / GetProtocol.
+    handler
+        .handle_control(hid_setup(HIDRequest::SetProtocol as u8, 0), &[])
+        .unwrap();
+    assert_eq!(
+        handler
+            .handle_control(hid_setup(HIDRequest::GetProtocol as u8, 0), &[])
+            .unwrap(),
+        vec![0]
+    );
+
+    // SetIdle(report_id = 5, idle_rate = 9) / GetIdle(report_id = 5).
+    let report_id = 5u8;
+    let idle_rate = 9u8;
+    let w_value = u16::from_le_bytes([report_id, idle_rate]);
+    handler
+        .handle_control(hid_setup(HIDRequest::SetIdle as u8, w_value), &[])
+        .unwrap();
+    assert_eq!(
+        handler
+            .handle_control(
+                hid_setup(HIDRequest::GetIdle as u8, report_id as u16),
+                &[]
+            )
+            .unwrap(),
+        vec![idle_rate]
+    );
+
+    // GetReport for an output/feature report (report_type != 1) is
+    // outside what this handler supports: it must stall the transfer
+    // rather than panic the process.
+    let w_value = u16::from_le_bytes([0, 2]); // report_type = 2 (output)
+    assert!(handler
+        .handle_control(hid_setup(HIDRequest::GetReport as u8, w_value), &[])
+        .is_err());
+
+    // A "Get*" request sent with the wrong (H2D) direction bit is
+    // syntactically valid but unsupported; it must stall too, not
+    // panic the process.
+    assert!(handler
+        .handle_control(
+            hid_setup_dir(0x21, HIDRequest::GetReport as u8, 0),
+            &[]
+        )
+        .is_err());
+}
+
+#[test]
+fn test_derive_serial_is_deterministic() {
+    // The same token must always derive the same serial, so re-plugging
+    // an authenticator enumerates with a stable identity.
+    let token = crate::crypto::tests::get_token().unwrap();
+    let serial = derive_serial(&token);
+    assert_eq!(serial.len(), 16);
+    assert!(serial.chars().all(|c| c.is_ascii_hexdigit()));
+    assert_eq!(serial, derive_serial(&token));
+}
+
+#[test]
+fn test_fnv1a_64_matches_published_test_vectors() {
+    // http://www.isthe.com/chongo/src/fnv/test_fnv.c
+    assert_eq!(fnv1a_64(b""), 0xcbf29ce484222325);
+    assert_eq!(fnv1a_64(b"a"), 0xaf63dc4c8601ec8c);
+}
+
+#[test]
+fn test_get_string_descriptor_truncates_long_string() {
+    // A manufacturer string long enough (and non-ASCII enough) to push
+    // the encoded descriptor past 255 bytes must be truncated, with
+    // bLength matching the bytes actually written, not wrapping around
+    // u8::MAX.
+    let token = crate::crypto::tests::get_token().unwrap();
+    let dev = Device::with_config(
+        &token,
+        &prompt::Pinentry {},
+        DeviceConfig {
+            manufacturer: "é".repeat(200),
+            ..DeviceConfig::default()
+        },
+    );
+    let mut buf = [0u8; 255];
+    let mut sink = std::io::Cursor::new(&mut buf[..]);
+    dev.get_string_descriptor(1, &mut sink).unwrap();
+    let written = sink.position() as usize;
+    assert!(written <= 255);
+    assert_eq!(buf[0] as usize, written);
+    assert_eq!(buf[1], DT::String.to_primitive());
+    // An odd payload length would mean a UTF-16 code unit got split.
+    assert_eq!((written - 2) % 2, 0);
+}